@@ -17,13 +17,17 @@
 
 //! Temporal quantification.
 
-use core::cmp;
+use core::convert::TryFrom;
 use core::fmt;
 use core::ops::{Add, AddAssign, Sub, SubAssign};
+use core::sync::atomic::{AtomicU64, Ordering};
 use crate::error::Error;
 use crate::sys::time;
 use crate::sys_common::FromInner;
-use crate::sync::SgxThreadMutex;
+#[cfg(feature = "trusted_time")]
+use sgx_tse::{rsgx_close_pse_session, rsgx_create_pse_session, rsgx_get_trusted_time};
+#[cfg(feature = "trusted_time")]
+use sgx_types::{sgx_status_t, sgx_time_source_nonce_t};
 
 pub use core::time::Duration;
 
@@ -93,8 +97,6 @@ impl Instant {
     }
 
     pub(crate) fn _now() -> Instant {
-        let os_now = time::Instant::now();
-
         // And here we come upon a sad state of affairs. The whole point of
         // `Instant` is that it's monotonically increasing. We've found in the
         // wild, however, that it's not actually monotonically increasing for
@@ -114,29 +116,32 @@ impl Instant {
         // It seems that this just happens a lot in the wild.
         // We're seeing panics across various platforms where consecutive calls
         // to `Instant::now`, such as via the `elapsed` function, are panicking
-        // as they're going backwards. Placed here is a last-ditch effort to try
-        // to fix things up. We keep a global "latest now" instance which is
+        // as they're going backwards. `monotonize` is a last-ditch effort to
+        // try to fix things up: it keeps a global "latest now" value which is
         // returned instead of what the OS says if the OS goes backwards.
-        //
-        // To hopefully mitigate the impact of this, a few platforms are
-        // whitelisted as "these at least haven't gone backwards yet".
+        Instant(monotonize(time::Instant::now()))
+    }
+
+    /// Returns an instant corresponding to "now", or an error if the
+    /// untrusted host reported a reading that regressed behind the latest
+    /// previously observed instant by more than a small tolerance.
+    ///
+    /// `now()` silently clamps such a regression so that `Instant` stays
+    /// monotonic; `try_now()` is for callers that instead want to treat a
+    /// large host-induced jump backwards as a possible attack signal.
+    ///
+    #[cfg(feature = "untrusted_time")]
+    pub fn try_now() -> Result<Instant, MonotonicityError> {
+        let raw = time::Instant::now();
         if time::Instant::actually_monotonic() {
-            return Instant(os_now);
+            return Ok(Instant(raw));
         }
-
-        static LOCK: SgxThreadMutex = SgxThreadMutex::new();
-        static mut LAST_NOW: time::Instant = time::Instant::zero();
-        unsafe {
-            let r = LOCK.lock();
-            let now = if r.is_ok() {
-                let now = cmp::max(LAST_NOW, os_now);
-                LAST_NOW = now;
-                LOCK.unlock();
-                now
-            } else {
-                os_now
-            };
-            Instant(now)
+        match instant_to_nanos(&raw) {
+            Some(now) => match ratchet(&MONO, now) {
+                (clamped, None) => Ok(Instant(nanos_to_instant(clamped))),
+                (_, Some(skew)) => Err(MonotonicityError(skew)),
+            },
+            None => Ok(Instant(raw)),
         }
     }
 
@@ -196,6 +201,25 @@ impl Instant {
     pub fn get_tup(&self) -> (i64, i64) {
         self.0.get_tup()
     }
+
+    /// Reconstructs an `Instant` from the `(sec, nsec)` pair previously
+    /// produced by [`get_tup`], returning `None` if `nsec` is outside
+    /// `0..1_000_000_000` or `sec` is negative.
+    ///
+    /// This is the inverse of `get_tup`, letting an `Instant` computed in one
+    /// enclave call be marshalled across an OCALL or into sealed storage and
+    /// rebuilt faithfully on the other side.
+    ///
+    /// [`get_tup`]: Instant::get_tup
+    ///
+    pub fn from_tup(sec: i64, nsec: i64) -> Option<Instant> {
+        if sec < 0 || !(0..1_000_000_000).contains(&nsec) {
+            return None;
+        }
+        time::Instant::zero()
+            .checked_add_duration(&Duration::new(sec as u64, nsec as u32))
+            .map(Instant)
+    }
 }
 
 impl Add<Duration> for Instant {
@@ -269,6 +293,29 @@ impl SystemTime {
         SystemTime(time::SystemTime::now())
     }
 
+    /// Returns the system time corresponding to "now", or an error if the
+    /// untrusted host reported a reading that regressed behind the latest
+    /// previously observed reading by more than a small tolerance.
+    ///
+    /// Unlike [`Instant::try_now`], a successful regression-free reading is
+    /// returned as-is rather than clamped: `SystemTime` isn't monotonic by
+    /// design, so there's nothing to clamp it to. This only guards against
+    /// the host clock jumping backwards by a suspiciously large amount.
+    ///
+    #[cfg(feature = "untrusted_time")]
+    pub fn try_now() -> Result<SystemTime, MonotonicityError> {
+        let raw = time::SystemTime::now();
+        match system_time_to_nanos(&raw) {
+            Some(now) => match ratchet(&MONO_SYSTEM, now) {
+                (_, None) => Ok(SystemTime(raw)),
+                (_, Some(skew)) => Err(MonotonicityError(skew)),
+            },
+            // Couldn't represent the reading as nanoseconds (e.g. it
+            // predates `UNIX_EPOCH`); nothing to compare it against.
+            None => Ok(SystemTime(raw)),
+        }
+    }
+
     /// Returns the amount of time elapsed from an earlier point in time.
     ///
     /// This function may fail because measurements taken earlier are not
@@ -334,6 +381,53 @@ impl SystemTime {
     pub fn get_tup(&self) -> (i64, i64) {
         self.0.get_tup()
     }
+
+    /// Reconstructs a `SystemTime` from the `(sec, nsec)` pair previously
+    /// produced by [`get_tup`], returning `None` if `nsec` is outside
+    /// `0..1_000_000_000` or the pair overflows the representable range.
+    ///
+    /// This is the inverse of `get_tup`, letting a `SystemTime` computed in
+    /// one enclave call be marshalled across an OCALL or into sealed storage
+    /// and rebuilt faithfully on the other side.
+    ///
+    /// [`get_tup`]: SystemTime::get_tup
+    ///
+    pub fn from_tup(sec: i64, nsec: i64) -> Option<SystemTime> {
+        if !(0..1_000_000_000).contains(&nsec) {
+            return None;
+        }
+        if sec >= 0 {
+            return UNIX_EPOCH.checked_add(Duration::new(sec as u64, nsec as u32));
+        }
+        let secs_before_epoch = sec.checked_neg()?;
+        let before_epoch = if nsec == 0 {
+            Duration::new(secs_before_epoch as u64, 0)
+        } else {
+            Duration::new((secs_before_epoch - 1) as u64, (1_000_000_000 - nsec) as u32)
+        };
+        UNIX_EPOCH.checked_sub(before_epoch)
+    }
+
+    /// Returns the number of nanoseconds since [`UNIX_EPOCH`], or `None` if
+    /// `self` predates the epoch or the value overflows a `u128`.
+    ///
+    pub fn as_unix_nanos(&self) -> Option<u128> {
+        self.duration_since(UNIX_EPOCH).ok().map(|d| d.as_nanos())
+    }
+
+    /// Returns the `SystemTime` that is `nanos` nanoseconds after
+    /// [`UNIX_EPOCH`], the inverse of [`as_unix_nanos`].
+    ///
+    /// Together these let a single 128-bit integer be marshalled across an
+    /// OCALL or into sealed storage as a durable timestamp.
+    ///
+    /// [`as_unix_nanos`]: SystemTime::as_unix_nanos
+    ///
+    pub fn from_unix_nanos(nanos: u128) -> Option<SystemTime> {
+        let secs = u64::try_from(nanos / 1_000_000_000).ok()?;
+        let subsec_nanos = (nanos % 1_000_000_000) as u32;
+        UNIX_EPOCH.checked_add(Duration::new(secs, subsec_nanos))
+    }
 }
 
 impl Add<Duration> for SystemTime {
@@ -418,8 +512,216 @@ impl fmt::Display for SystemTimeError {
     }
 }
 
+/// An error returned from [`Instant::try_now`] and [`SystemTime::try_now`]
+/// when the untrusted host reports a clock reading that falls behind the
+/// latest previously observed reading by more than a small tolerance.
+///
+/// This is distinct from the silent clamping [`Instant::now`] performs (and
+/// the unchecked pass-through [`SystemTime::now`] performs): it lets callers
+/// that care about a forged or rolled-back host clock treat a large
+/// regression as a possible attack signal instead of ignoring it.
+///
+#[derive(Clone, Debug)]
+pub struct MonotonicityError(Duration);
+
+impl MonotonicityError {
+    /// Returns the magnitude of the backwards jump that was detected.
+    ///
+    pub fn skew(&self) -> Duration {
+        self.0
+    }
+}
+
+impl Error for MonotonicityError {
+    fn description(&self) -> &str {
+        "clock reading regressed beyond the allowed tolerance"
+    }
+}
+
+impl fmt::Display for MonotonicityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "clock went backwards by {:?}", self.0)
+    }
+}
+
 impl FromInner<time::SystemTime> for SystemTime {
     fn from_inner(time: time::SystemTime) -> SystemTime {
         SystemTime(time)
     }
+}
+
+/// A point in time reported by the SGX Platform Service's trusted time
+/// source.
+///
+/// Unlike [`Instant`] and [`SystemTime`], which are ultimately read from the
+/// untrusted host via an OCALL, `TrustedTime` goes through the SGX Platform
+/// Service Enclave (PSE), so a malicious host cannot forge or roll back the
+/// reading without it being detected. Every reading is tagged with the PSE's
+/// `time_source_nonce`, which changes whenever its backing counter resets;
+/// two readings are only comparable when their nonces match.
+///
+/// Requires the `trusted_time` feature, which pulls in `sgx_tse`.
+///
+#[cfg(feature = "trusted_time")]
+#[derive(Copy, Clone, Debug)]
+pub struct TrustedTime {
+    timestamp: u64,
+    nonce: sgx_time_source_nonce_t,
+}
+
+#[cfg(feature = "trusted_time")]
+impl TrustedTime {
+    /// Returns the current trusted time, as reported by the SGX Platform
+    /// Service.
+    ///
+    /// This opens and closes a PSE session for the duration of the call, so
+    /// it is considerably more expensive than [`Instant::now`] or
+    /// [`SystemTime::now`] and shouldn't be polled in a hot loop.
+    ///
+    pub fn now() -> Result<TrustedTime, TrustedTimeError> {
+        rsgx_create_pse_session().map_err(TrustedTimeError::Sgx)?;
+        let result = rsgx_get_trusted_time();
+        rsgx_close_pse_session().map_err(TrustedTimeError::Sgx)?;
+        let (timestamp, nonce) = result.map_err(TrustedTimeError::Sgx)?;
+        Ok(TrustedTime { timestamp, nonce })
+    }
+
+    /// Returns the amount of time elapsed from another trusted time reading
+    /// to this one.
+    ///
+    /// Returns [`TrustedTimeError::NonceMismatch`] if `earlier` was taken
+    /// against a different `time_source_nonce`: the PSE's trusted counter
+    /// was reset between the two readings, so they aren't comparable.
+    ///
+    pub fn duration_since(&self, earlier: &TrustedTime) -> Result<Duration, TrustedTimeError> {
+        if self.nonce != earlier.nonce {
+            return Err(TrustedTimeError::NonceMismatch);
+        }
+        Ok(Duration::from_secs(self.timestamp.saturating_sub(earlier.timestamp)))
+    }
+
+    /// Returns the amount of trusted time elapsed since this reading was
+    /// taken.
+    ///
+    pub fn elapsed(&self) -> Result<Duration, TrustedTimeError> {
+        TrustedTime::now()?.duration_since(self)
+    }
+}
+
+/// An error returned from [`TrustedTime::now`], [`TrustedTime::duration_since`]
+/// and [`TrustedTime::elapsed`].
+///
+#[cfg(feature = "trusted_time")]
+#[derive(Clone, Debug)]
+pub enum TrustedTimeError {
+    /// The underlying SGX Platform Service call failed.
+    Sgx(sgx_status_t),
+    /// The two readings were taken against different trusted counters (the
+    /// PSE's counter was reset between them), so they can't be compared.
+    NonceMismatch,
+}
+
+#[cfg(feature = "trusted_time")]
+impl Error for TrustedTimeError {
+    fn description(&self) -> &str {
+        match self {
+            TrustedTimeError::Sgx(_) => "SGX Platform Service call failed",
+            TrustedTimeError::NonceMismatch => "trusted time readings are not comparable",
+        }
+    }
+}
+
+#[cfg(feature = "trusted_time")]
+impl fmt::Display for TrustedTimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrustedTimeError::Sgx(status) => {
+                write!(f, "SGX Platform Service call failed: {}", status)
+            }
+            TrustedTimeError::NonceMismatch => {
+                write!(f, "trusted time readings come from different time sources")
+            }
+        }
+    }
+}
+
+// Lock-free "latest now" store used by `Instant::_now` (through `monotonize`)
+// and `Instant::try_now`. SGX enclaves are 64-bit only, so unlike upstream std we
+// don't need a 32-bit fallback: a raw instant always fits in a `u64`
+// nanosecond count (`secs * 1_000_000_000 + nanos`, good for ~584 years).
+//
+// `MONO` stores that count, with `0` reserved as an "uninitialized" sentinel
+// (a genuine reading of `0` is bumped to `1` so it can't be confused with it).
+static MONO: AtomicU64 = AtomicU64::new(0);
+
+fn instant_to_nanos(instant: &time::Instant) -> Option<u64> {
+    let (sec, nsec) = instant.get_tup();
+    u64::try_from(sec).ok()?.checked_mul(1_000_000_000)?.checked_add(nsec as u64)
+}
+
+fn system_time_to_nanos(time: &time::SystemTime) -> Option<u64> {
+    let (sec, nsec) = time.get_tup();
+    u64::try_from(sec).ok()?.checked_mul(1_000_000_000)?.checked_add(nsec as u64)
+}
+
+fn nanos_to_instant(nanos: u64) -> time::Instant {
+    time::Instant::zero()
+        .checked_add_duration(&Duration::from_nanos(nanos))
+        .expect("monotonic nanosecond count overflowed Instant")
+}
+
+/// Clamps `raw` so that, barring overflow, it never compares less than any
+/// instant previously returned by this function.
+///
+/// `Instant::now()` isn't actually guaranteed to be monotonic on every OS and
+/// hardware combination; this papers over the regressions we've observed in
+/// the wild without requiring a lock on the hot path.
+fn monotonize(raw: time::Instant) -> time::Instant {
+    if time::Instant::actually_monotonic() {
+        return raw;
+    }
+
+    match instant_to_nanos(&raw) {
+        Some(now) => nanos_to_instant(ratchet(&MONO, now).0),
+        // Couldn't represent the reading as nanoseconds (e.g. it predates
+        // `time::Instant::zero()`); fall back to returning it unclamped.
+        None => raw,
+    }
+}
+
+/// Maximum backwards jump that `try_now()` silently absorbs before reporting
+/// it as a [`MonotonicityError`] instead. `now()` clamps a regression of any
+/// size and never consults this tolerance.
+const MONOTONICITY_EPSILON_NANOS: u64 = 1_000_000; // 1ms
+
+/// Store backing [`SystemTime::try_now`], separate from [`MONO`] since
+/// `SystemTime::now()` (unlike `Instant::now()`) is never clamped and so
+/// doesn't maintain one of its own.
+static MONO_SYSTEM: AtomicU64 = AtomicU64::new(0);
+
+/// Advances `store` to `now` if `now` is greater than the value already
+/// there, and returns the authoritative (i.e. monotone) value.
+///
+/// The second element of the pair is `Some(skew)` when `now` fell behind the
+/// previous maximum by more than [`MONOTONICITY_EPSILON_NANOS`], for callers
+/// (namely `try_now`) that want to know about the regression instead of
+/// having it silently absorbed.
+fn ratchet(store: &AtomicU64, now: u64) -> (u64, Option<Duration>) {
+    // Bump a genuine `0` reading so it can't be confused with the
+    // "uninitialized" sentinel.
+    let now = if now == 0 { 1 } else { now };
+
+    let mut last = store.load(Ordering::Relaxed);
+    loop {
+        if last != 0 && now <= last {
+            let skew = last - now;
+            let regression =
+                if skew > MONOTONICITY_EPSILON_NANOS { Some(Duration::from_nanos(skew)) } else { None };
+            return (last, regression);
+        }
+        match store.compare_exchange_weak(last, now, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return (now, None),
+            Err(observed) => last = observed,
+        }
+    }
 }
\ No newline at end of file